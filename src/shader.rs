@@ -1,6 +1,13 @@
 use gl;
 use gl::types::{GLint, GLuint, GLchar};
+use std::error::Error;
+use std::fmt;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::fs;
 use std::io::fs::File;
+use std::io::IoError;
+use std::mem;
 use std::ptr;
 use std::string::String;
 use std::vec::Vec;
@@ -11,25 +18,370 @@ use numvec::Vec3f;
 
 pub struct Shader {
     program : Program,
+    uniforms : HashMap<String, (Uniform, UniformValue)>,
+    watch : Option<ShaderWatch>,
+}
+
+/// Remembers how a file-backed `Shader` was built so `reload_if_changed`
+/// can tell whether either source file has changed on disk, and rebuild
+/// the same way if so.
+struct ShaderWatch {
+    vertex_path : Path,
+    fragment_path : Path,
+    version : ShaderVersion,
+    defines : Vec<(String, String)>,
+    vertex_modified : u64,
+    fragment_modified : u64,
 }
 
 pub struct Uniform {
     id : GLint,
 }
 
+impl Clone for Uniform {
+    fn clone(&self) -> Uniform { *self }
+}
+impl Copy for Uniform {}
+
+/// The kind of value a declared uniform holds, used to catch `set` calls
+/// made with the wrong `UniformValue` variant for a given name.
+pub enum UniformType {
+    F32,
+    Vec3f,
+    Mat4,
+    TextureUnit,
+}
+
+impl Clone for UniformType {
+    fn clone(&self) -> UniformType { *self }
+}
+impl Copy for UniformType {}
+
+impl PartialEq for UniformType {
+    fn eq(&self, other: &UniformType) -> bool {
+        match (self, other) {
+            (&UniformType::F32, &UniformType::F32) => true,
+            (&UniformType::Vec3f, &UniformType::Vec3f) => true,
+            (&UniformType::Mat4, &UniformType::Mat4) => true,
+            (&UniformType::TextureUnit, &UniformType::TextureUnit) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Show for UniformType {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UniformType::F32 => write!(out, "f32"),
+            UniformType::Vec3f => write!(out, "vec3f"),
+            UniformType::Mat4 => write!(out, "mat4"),
+            UniformType::TextureUnit => write!(out, "texture unit"),
+        }
+    }
+}
+
+/// A named uniform's value, tagged with its `UniformType` by construction.
+pub enum UniformValue {
+    F32(f32),
+    Vec3f(Vec3f),
+    Mat4(Mat4),
+    TextureUnit(i32),
+}
+
+impl UniformValue {
+    fn kind(&self) -> UniformType {
+        match *self {
+            UniformValue::F32(_) => UniformType::F32,
+            UniformValue::Vec3f(_) => UniformType::Vec3f,
+            UniformValue::Mat4(_) => UniformType::Mat4,
+            UniformValue::TextureUnit(_) => UniformType::TextureUnit,
+        }
+    }
+}
+
+/// Selects which `#version` header is prepended to shader source before it
+/// is handed to the driver, so a single `.glsl` file can target either a
+/// desktop GL context or an ES/WebGL-class one.
+pub enum ShaderVersion {
+    /// Desktop OpenGL 3.3 core profile.
+    Glsl330,
+    /// OpenGL ES 2.0 / WebGL 1.0.
+    Gles2,
+}
+
+impl Clone for ShaderVersion {
+    fn clone(&self) -> ShaderVersion { *self }
+}
+impl Copy for ShaderVersion {}
+
+impl ShaderVersion {
+    fn header(&self) -> &'static str {
+        match *self {
+            ShaderVersion::Glsl330 => "#version 330 core\n",
+            ShaderVersion::Gles2 => "#version 100\n#define GLES2_RENDERER\n",
+        }
+    }
+}
+
+/// The shading stage a piece of source or a compiled shader object belongs to.
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Geometry,
+}
+
+impl Clone for ShaderStage {
+    fn clone(&self) -> ShaderStage { *self }
+}
+impl Copy for ShaderStage {}
+
+impl fmt::Show for ShaderStage {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ShaderStage::Vertex => write!(out, "vertex"),
+            ShaderStage::Fragment => write!(out, "fragment"),
+            ShaderStage::Geometry => write!(out, "geometry"),
+        }
+    }
+}
+
+fn gl_shader_type(stage: ShaderStage) -> u32 {
+    match stage {
+        ShaderStage::Vertex => gl::VERTEX_SHADER,
+        ShaderStage::Fragment => gl::FRAGMENT_SHADER,
+        ShaderStage::Geometry => gl::GEOMETRY_SHADER,
+    }
+}
+
+/// Everything that can go wrong while building a `Shader`.
+pub enum ShaderError {
+    /// Reading a shader source file from disk failed.
+    Io(IoError),
+    /// A shader source file was not valid UTF-8.
+    NotUtf8(Path),
+    /// `glCompileShader` failed for the given stage; `log` is the driver's
+    /// info log. `header_lines` is how many lines of version/define header
+    /// were prepended before compiling. Any `#version` line the source
+    /// already had (with any blank lines before it) was removed first, and
+    /// `#include`d files were spliced in before that, so line numbers in
+    /// `log` are offset from the original source file by exactly
+    /// `header_lines` only for a source that had neither.
+    Compile { stage: ShaderStage, log: String, header_lines: uint },
+    /// `glLinkProgram` failed; `log` is the driver's info log.
+    Link(String),
+    /// `Shader::set` was called with a name that has no cached location:
+    /// either no `ShaderBuilder` declared it, or the linker optimised it away.
+    UnknownUniform(String),
+    /// `Shader::set` was called with a value whose type doesn't match what
+    /// was declared for this uniform name.
+    UniformTypeMismatch { name: String, expected: UniformType, found: UniformType },
+}
+
+impl fmt::Show for ShaderError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ShaderError::Io(ref err) => write!(out, "shader i/o error: {}", err),
+            ShaderError::NotUtf8(ref path) =>
+                write!(out, "file at '{}' is not valid UTF-8", path.display()),
+            ShaderError::Compile { ref stage, ref log, header_lines } =>
+                write!(out,
+                       "{} shader compilation failed ({} header line(s) were injected \
+                        in place of any #version line the source had):\n{}",
+                       stage, header_lines, log),
+            ShaderError::Link(ref log) =>
+                write!(out, "shader linking failed:\n{}", log),
+            ShaderError::UnknownUniform(ref name) =>
+                write!(out, "no uniform named '{}' is cached on this shader \
+                            (not declared, or optimised away by the linker)", name),
+            ShaderError::UniformTypeMismatch { ref name, expected, found } =>
+                write!(out, "uniform '{}' was declared as {} but set as {}",
+                       name, expected, found),
+        }
+    }
+}
+
+impl Error for ShaderError {
+    fn description(&self) -> &str {
+        match *self {
+            ShaderError::Io(_) => "could not read shader source",
+            ShaderError::NotUtf8(_) => "shader source is not valid UTF-8",
+            ShaderError::Compile { .. } => "shader compilation failed",
+            ShaderError::Link(_) => "shader linking failed",
+            ShaderError::UnknownUniform(_) => "unknown uniform name",
+            ShaderError::UniformTypeMismatch { .. } => "uniform type mismatch",
+        }
+    }
+}
+
 impl Shader {
-    pub fn new_from_files(vertex_path: &Path, fragment_path: &Path)
-            -> Result<Shader, String> {
-        Shader::new_from_source(try!(file_contents(vertex_path)).as_slice(),
-                                try!(file_contents(fragment_path)).as_slice())
+    /// Loads and compiles a shader from a vertex/fragment source file pair.
+    ///
+    /// Before compiling, each file is run through a small preprocessor: any
+    /// `#include "path"` directive is replaced with the contents of `path`
+    /// (resolved relative to the directory of the file doing the including,
+    /// recursively, with repeat includes silently skipped so shared headers
+    /// with diamond or cyclic includes don't blow up), and `defines` is
+    /// emitted as `#define NAME VALUE` lines right after the version header.
+    ///
+    /// The resulting `Shader` remembers `vertex_path`/`fragment_path` and
+    /// how it was built, so `reload_if_changed` can later recompile it in
+    /// place from the same files.
+    pub fn new_from_files(vertex_path: &Path, fragment_path: &Path, version: ShaderVersion,
+                          defines: &[(&str, &str)]) -> Result<Shader, ShaderError> {
+        let vertex_source = try!(preprocess_file(vertex_path));
+        let fragment_source = try!(preprocess_file(fragment_path));
+        let mut shader = try!(Shader::new_from_source(vertex_source.as_slice(),
+                                                       fragment_source.as_slice(),
+                                                       version, defines));
+        shader.watch = Some(ShaderWatch {
+            vertex_path: vertex_path.clone(),
+            fragment_path: fragment_path.clone(),
+            version: version,
+            defines: defines.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect(),
+            vertex_modified: try!(modified_time(vertex_path)),
+            fragment_modified: try!(modified_time(fragment_path)),
+        });
+        Ok(shader)
+    }
+
+    pub fn new_from_source(vertex_source: &str, fragment_source: &str, version: ShaderVersion,
+                           defines: &[(&str, &str)]) -> Result<Shader, ShaderError> {
+        let vertex = try!(CompiledShader::compile(ShaderStage::Vertex, vertex_source,
+                                                  version, defines));
+        let fragment = try!(CompiledShader::compile(ShaderStage::Fragment, fragment_source,
+                                                    version, defines));
+        let program = try!(Program::link(vertex, fragment, None));
+        Ok(Shader { program: program, uniforms: HashMap::new(), watch: None })
     }
 
-    pub fn new_from_source(vertex_source: &str, fragment_source: &str)
-            -> Result<Shader, String> {
-        let vertex = try!(VertexShader::compile(vertex_source));
-        let fragment = try!(FragmentShader::compile(fragment_source));
-        let program = try!(Program::link(vertex, fragment));
-        Ok(Shader { program: program })
+    /// Like `new_from_source`, but also compiles and attaches a geometry
+    /// stage, for things like GPU-side sprite billboard expansion or
+    /// thick-line wireframe debug overlays that need a third stage between
+    /// the vertex and fragment shaders.
+    pub fn new_from_source_with_geometry(vertex_source: &str, geometry_source: &str,
+                                         fragment_source: &str, version: ShaderVersion,
+                                         defines: &[(&str, &str)]) -> Result<Shader, ShaderError> {
+        let vertex = try!(CompiledShader::compile(ShaderStage::Vertex, vertex_source,
+                                                  version, defines));
+        let geometry = try!(CompiledShader::compile(ShaderStage::Geometry, geometry_source,
+                                                     version, defines));
+        let fragment = try!(CompiledShader::compile(ShaderStage::Fragment, fragment_source,
+                                                    version, defines));
+        let program = try!(Program::link(vertex, fragment, Some(geometry)));
+        Ok(Shader { program: program, uniforms: HashMap::new(), watch: None })
+    }
+
+    /// Re-reads the vertex/fragment files this `Shader` was loaded from (via
+    /// `new_from_files` or `ShaderBuilder::new_from_files(..).build()`) and
+    /// recompiles it if either has a newer modification time than when it
+    /// was last built. Returns `Ok(false)` without touching the shader if
+    /// nothing changed, or if the shader wasn't loaded from files. On a
+    /// successful rebuild, the new program is swapped in atomically and any
+    /// uniforms already cached on this `Shader` (as declared to a
+    /// `ShaderBuilder`) are re-resolved by name against it and redispatched
+    /// to their last-set value, since the new program starts with all of
+    /// its uniforms zeroed; on a failed
+    /// rebuild (e.g. a syntax error from editing mid-session), the old
+    /// program is left bound and in use, and the error is returned so the
+    /// caller can log it and keep going.
+    pub fn reload_if_changed(&mut self) -> Result<bool, ShaderError> {
+        let (vertex_path, fragment_path, version, defines, vertex_modified, fragment_modified) = {
+            let watch = match self.watch {
+                Some(ref watch) => watch,
+                None => return Ok(false),
+            };
+            (watch.vertex_path.clone(), watch.fragment_path.clone(), watch.version,
+             watch.defines.clone(), watch.vertex_modified, watch.fragment_modified)
+        };
+
+        let new_vertex_modified = try!(modified_time(&vertex_path));
+        let new_fragment_modified = try!(modified_time(&fragment_path));
+        if new_vertex_modified <= vertex_modified && new_fragment_modified <= fragment_modified {
+            return Ok(false);
+        }
+
+        let define_refs: Vec<(&str, &str)> =
+            defines.iter().map(|&(ref n, ref v)| (n.as_slice(), v.as_slice())).collect();
+        let rebuilt = Shader::new_from_files(&vertex_path, &fragment_path, version,
+                                             define_refs.as_slice());
+
+        match self.watch {
+            Some(ref mut watch) => {
+                watch.vertex_modified = new_vertex_modified;
+                watch.fragment_modified = new_fragment_modified;
+            }
+            None => unreachable!(),
+        }
+
+        match rebuilt {
+            Ok(fresh) => {
+                // Capture the caller's binding, and whether it was this
+                // shader's own (about to be replaced) program, before
+                // dropping the old `Program` below: dropping it deletes its
+                // GL name, and if it was still current that only flags it
+                // for deletion, so reading the binding back afterward risks
+                // seeing a name GL has since freed once the new program
+                // replaces it as current.
+                let previously_bound = current_program();
+                let was_bound = previously_bound == self.program.id;
+                self.program = fresh.program;
+                // The new program is a distinct GL object, so every uniform
+                // it declares starts zeroed; re-resolve each previously
+                // cached uniform's location against it and redispatch the
+                // value it was last set to, not just its type.
+                let previous_uniforms = mem::replace(&mut self.uniforms, HashMap::new());
+                self.bind();
+                for (name, (_, value)) in previous_uniforms.into_iter() {
+                    if let Some(uniform) = self.get_uniform(name.as_slice()) {
+                        self.dispatch_uniform(uniform, &value);
+                        self.uniforms.insert(name, (uniform, value));
+                    }
+                }
+                // If the caller had this shader's own (now-replaced) program
+                // bound, leave the rebuilt one bound in its place instead of
+                // restoring a GL name that was just deleted; otherwise put
+                // back whatever unrelated program was bound before.
+                if !was_bound {
+                    check_gl!(gl::UseProgram(previously_bound));
+                }
+                Ok(true)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Looks up the cached location for `name` (as declared to a
+    /// `ShaderBuilder`) and dispatches `value` to the matching
+    /// `gl::Uniform*` call, remembering it as `name`'s new value so a later
+    /// `reload_if_changed` can redispatch it against a rebuilt program.
+    /// Fails if `name` wasn't declared, or if `value`'s type doesn't match
+    /// what was declared for it.
+    pub fn set(&mut self, name: &str, value: UniformValue) -> Result<(), ShaderError> {
+        let uniform = match self.uniforms.get(name) {
+            None => return Err(ShaderError::UnknownUniform(name.to_string())),
+            Some(&(uniform, ref stored)) => {
+                let expected = stored.kind();
+                let found = value.kind();
+                if expected != found {
+                    return Err(ShaderError::UniformTypeMismatch {
+                        name: name.to_string(), expected: expected, found: found,
+                    });
+                }
+                uniform
+            }
+        };
+        self.dispatch_uniform(uniform, &value);
+        self.uniforms.insert(name.to_string(), (uniform, value));
+        Ok(())
+    }
+
+    fn dispatch_uniform(&self, uniform: Uniform, value: &UniformValue) {
+        match *value {
+            UniformValue::F32(v) => self.set_uniform_f32(uniform, v),
+            UniformValue::Vec3f(ref v) => self.set_uniform_vec3f(uniform, v),
+            UniformValue::Mat4(ref v) => self.set_uniform_mat4(uniform, v),
+            UniformValue::TextureUnit(v) => self.set_uniform_i32(uniform, v),
+        }
     }
 
     pub fn bind(&self) {
@@ -67,43 +419,154 @@ impl Shader {
     }
 }
 
-struct VertexShader { id : GLuint }
-impl VertexShader {
-    fn compile(source: &str) -> Result<VertexShader, String> {
-        compile_any(gl::VERTEX_SHADER, source)
-            .map(|id| VertexShader{ id: id })
+/// Where a `ShaderBuilder` reads its vertex/fragment source from.
+enum BuilderSource<'a> {
+    /// In-memory source; the resulting `Shader` has no file watch.
+    Source(&'a str, &'a str),
+    /// Source files; the resulting `Shader` is watched, so
+    /// `Shader::reload_if_changed` can recompile it (and re-resolve the
+    /// declared uniforms below) when they change on disk.
+    Files(Path, Path),
+}
+
+/// Builds a `Shader` from a declared set of uniforms instead of ad hoc
+/// `get_uniform`/`set_uniform_*` calls: every named uniform's location is
+/// resolved once at build time and cached on the resulting `Shader`, so
+/// later `Shader::set` calls skip `glGetUniformLocation` entirely.
+pub struct ShaderBuilder<'a> {
+    source : BuilderSource<'a>,
+    version : ShaderVersion,
+    uniforms : Vec<(String, UniformValue)>,
+}
+
+impl<'a> ShaderBuilder<'a> {
+    pub fn new(vertex_source: &'a str, fragment_source: &'a str, version: ShaderVersion)
+            -> ShaderBuilder<'a> {
+        ShaderBuilder {
+            source: BuilderSource::Source(vertex_source, fragment_source),
+            version: version,
+            uniforms: Vec::new(),
+        }
+    }
+
+    pub fn new_from_files(vertex_path: &Path, fragment_path: &Path, version: ShaderVersion)
+            -> ShaderBuilder<'a> {
+        ShaderBuilder {
+            source: BuilderSource::Files(vertex_path.clone(), fragment_path.clone()),
+            version: version,
+            uniforms: Vec::new(),
+        }
+    }
+
+    pub fn with_f32(mut self, name: &str, default: f32) -> ShaderBuilder<'a> {
+        self.uniforms.push((name.to_string(), UniformValue::F32(default)));
+        self
+    }
+
+    pub fn with_vec3f(mut self, name: &str, default: Vec3f) -> ShaderBuilder<'a> {
+        self.uniforms.push((name.to_string(), UniformValue::Vec3f(default)));
+        self
+    }
+
+    pub fn with_mat4(mut self, name: &str, default: Mat4) -> ShaderBuilder<'a> {
+        self.uniforms.push((name.to_string(), UniformValue::Mat4(default)));
+        self
+    }
+
+    pub fn with_texture_unit(mut self, name: &str, default: i32) -> ShaderBuilder<'a> {
+        self.uniforms.push((name.to_string(), UniformValue::TextureUnit(default)));
+        self
+    }
+
+    /// Compiles and links the shader, then resolves and caches the location
+    /// of every declared uniform, setting it to its default value. A
+    /// uniform the linker optimised away is simply not cached; a later
+    /// `Shader::set` for it fails with `ShaderError::UnknownUniform`, same
+    /// as any other undeclared name.
+    pub fn build(self) -> Result<Shader, ShaderError> {
+        let version = self.version;
+        let shader = match self.source {
+            BuilderSource::Source(vertex_source, fragment_source) =>
+                try!(Shader::new_from_source(vertex_source, fragment_source, version, &[])),
+            BuilderSource::Files(ref vertex_path, ref fragment_path) =>
+                try!(Shader::new_from_files(vertex_path, fragment_path, version, &[])),
+        };
+        Ok(bind_declared_uniforms(shader, self.uniforms))
     }
 }
-impl Drop for VertexShader {
-    fn drop(&mut self) { check_gl!(gl::DeleteShader(self.id)); }
+
+/// Resolves and caches the location of every `(name, default)` pair on
+/// `shader`, writing each default value to it.
+///
+/// `gl::Uniform*` always targets whatever program is currently bound via
+/// `glUseProgram`, not the program whose locations were just resolved, so
+/// `shader` is bound first; whatever program was bound before this call is
+/// restored afterward, so building a shader mid-frame doesn't silently
+/// un-bind the program the caller was already using.
+fn bind_declared_uniforms(mut shader: Shader, uniforms: Vec<(String, UniformValue)>) -> Shader {
+    let previously_bound = current_program();
+    shader.bind();
+    for (name, default) in uniforms.into_iter() {
+        if let Some(uniform) = shader.get_uniform(name.as_slice()) {
+            shader.dispatch_uniform(uniform, &default);
+            shader.uniforms.insert(name, (uniform, default));
+        }
+    }
+    check_gl!(gl::UseProgram(previously_bound));
+    shader
 }
 
+fn current_program() -> GLuint {
+    let mut id: GLint = 0;
+    check_gl_unsafe!(gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut id));
+    id as GLuint
+}
 
-struct FragmentShader { id : GLuint }
-impl FragmentShader {
-    fn compile(source: &str) -> Result<FragmentShader, String> {
-        compile_any(gl::FRAGMENT_SHADER, source)
-            .map(|id| FragmentShader{ id: id })
+/// A single compiled (but not yet linked) shader object for one stage.
+struct CompiledShader { id : GLuint }
+impl CompiledShader {
+    fn compile(stage: ShaderStage, source: &str, version: ShaderVersion,
+              defines: &[(&str, &str)]) -> Result<CompiledShader, ShaderError> {
+        compile_any(stage, source, version, defines).map(|id| CompiledShader{ id: id })
     }
 }
-impl Drop for FragmentShader {
+impl Drop for CompiledShader {
     fn drop(&mut self) { check_gl!(gl::DeleteShader(self.id)); }
 }
 
 
 struct Program { id : GLuint }
 impl Program {
-    fn link(vertex: VertexShader, fragment: FragmentShader)
-            -> Result<Program, String> {
+    /// Attaches `vertex`, `fragment`, and `geometry` (if present) to a new
+    /// program and links it. Every attached stage is detached again before
+    /// returning, whether linking succeeded or not, so that each
+    /// `CompiledShader`'s own `Drop` is left free to delete it the moment
+    /// it goes out of scope.
+    fn link(vertex: CompiledShader, fragment: CompiledShader, geometry: Option<CompiledShader>)
+            -> Result<Program, ShaderError> {
         let program = Program{ id: check_gl!(gl::CreateProgram()) };
         check_gl!(gl::AttachShader(program.id, vertex.id));
         check_gl!(gl::AttachShader(program.id, fragment.id));
+        if let Some(ref geometry) = geometry {
+            check_gl!(gl::AttachShader(program.id, geometry.id));
+        }
+
         check_gl!(gl::LinkProgram(program.id));
-        if link_succeeded(program.id) {
-            Ok(program)
+        let log = if link_succeeded(program.id) {
+            None
         } else {
-            let log = get_link_log(program.id);
-            Err(format!("Shader linking failed:\n{}", log))
+            Some(get_link_log(program.id))
+        };
+
+        check_gl!(gl::DetachShader(program.id, vertex.id));
+        check_gl!(gl::DetachShader(program.id, fragment.id));
+        if let Some(ref geometry) = geometry {
+            check_gl!(gl::DetachShader(program.id, geometry.id));
+        }
+
+        match log {
+            None => Ok(program),
+            Some(log) => Err(ShaderError::Link(log)),
         }
     }
 }
@@ -112,35 +575,127 @@ impl Drop for Program {
 }
 
 
-fn file_contents(path: &Path) -> Result<String, String> {
+fn file_contents(path: &Path) -> Result<String, ShaderError> {
     File::open(path)
     .and_then(|mut file| file.read_to_end())
-    .map_err(|e| String::from_str(e.desc))
+    .map_err(|e| ShaderError::Io(e))
     .and_then(|buffer| {
-        String::from_utf8(buffer).map_err(|_| {
-            format!("File at '{}' is not valid UTF-8.", path.display())
-        })
+        String::from_utf8(buffer).map_err(|_| ShaderError::NotUtf8(path.clone()))
     })
 }
 
 
-fn compile_any(shader_type: u32, source: &str) -> Result<GLuint, String> {
-    let id = check_gl!(gl::CreateShader(shader_type));
+fn modified_time(path: &Path) -> Result<u64, ShaderError> {
+    fs::stat(path).map(|stat| stat.modified).map_err(|e| ShaderError::Io(e))
+}
+
+
+/// Reads `path` and resolves its `#include` directives.
+fn preprocess_file(path: &Path) -> Result<String, ShaderError> {
+    let source = try!(file_contents(path));
+    let mut included = HashSet::new();
+    included.insert(path.clone());
+    resolve_includes(source.as_slice(), &path.dir_path(), &mut included)
+}
+
+
+/// Splices the contents of any `#include "path"`-directive target into
+/// `source` in place, resolving `path` relative to `dir`. `included` tracks
+/// every file already spliced in so a diamond or cyclic `#include` chain
+/// doesn't get pulled in twice or recurse forever.
+fn resolve_includes(source: &str, dir: &Path, included: &mut HashSet<Path>)
+        -> Result<String, ShaderError> {
+    let mut result = String::with_capacity(source.len());
+    for line in source.lines() {
+        match parse_include_directive(line) {
+            Some(include_name) => {
+                let include_path = dir.join(include_name);
+                if included.insert(include_path.clone()) {
+                    let include_source = try!(file_contents(&include_path));
+                    result.push_str(try!(resolve_includes(
+                        include_source.as_slice(), &include_path.dir_path(), included))
+                        .as_slice());
+                    result.push('\n');
+                }
+            }
+            None => {
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+    }
+    Ok(result)
+}
+
+
+/// Parses a line as `#include "path"`, returning `path` on a match.
+fn parse_include_directive(line: &str) -> Option<&str> {
+    let trimmed = line.trim();
+    if !trimmed.starts_with("#include") {
+        return None;
+    }
+    let rest = trimmed.slice_from("#include".len()).trim();
+    if rest.len() >= 2 && rest.starts_with("\"") && rest.ends_with("\"") {
+        Some(rest.slice(1, rest.len() - 1))
+    } else {
+        None
+    }
+}
+
+
+fn compile_any(stage: ShaderStage, source: &str, version: ShaderVersion,
+        defines: &[(&str, &str)]) -> Result<GLuint, ShaderError> {
+    let header = build_header(version, defines);
+    let header_lines = header.as_slice().chars().filter(|&c| c == '\n').count();
+    let versioned_source = with_version_header(header.as_slice(), source);
+
+    let id = check_gl!(gl::CreateShader(gl_shader_type(stage)));
     assert!(id != 0);
-    source.with_c_str(|c_str| {
+    versioned_source.as_slice().with_c_str(|c_str| {
         check_gl_unsafe!(gl::ShaderSource(id, 1, &c_str, ptr::null()));
     });
     check_gl!(gl::CompileShader(id));
     if compilation_succeeded(id) {
         Ok(id)
     } else {
-        let log = get_compilation_log(id);;
+        let log = get_compilation_log(id);
         check_gl!(gl::DeleteShader(id));
-        if shader_type == gl::VERTEX_SHADER {
-            Err(format!("Vertex shader compilation failed:\n{}", log))
-        } else {
-            Err(format!("Fragment shader compilation failed:\n{}", log))
+        Err(ShaderError::Compile { stage: stage, log: log, header_lines: header_lines })
+    }
+}
+
+
+/// Builds the `#version` line plus one `#define NAME VALUE` line per entry
+/// in `defines`, in order.
+fn build_header(version: ShaderVersion, defines: &[(&str, &str)]) -> String {
+    let mut header = String::from_str(version.header());
+    for &(name, value) in defines.iter() {
+        header.push_str(format!("#define {} {}\n", name, value).as_slice());
+    }
+    header
+}
+
+
+/// Prepends `header` to `source`, first stripping any `#version` directive
+/// `source` already has — the header's own `#version` line always wins.
+fn with_version_header(header: &str, source: &str) -> String {
+    let body = strip_version_directive(source);
+    let mut versioned = String::with_capacity(header.len() + body.len());
+    versioned.push_str(header);
+    versioned.push_str(body);
+    versioned
+}
+
+
+fn strip_version_directive(source: &str) -> &str {
+    let trimmed = source.trim_left();
+    if trimmed.starts_with("#version") {
+        match trimmed.find('\n') {
+            Some(newline) => trimmed.slice_from(newline + 1),
+            None => "",
         }
+    } else {
+        source
     }
 }
 
@@ -183,3 +738,79 @@ fn get_link_log(shader_id: GLuint) -> String {
             shader_id, log_length, ptr::mut_null(), log_buffer_ptr));
     String::from_utf8(log_buffer).unwrap()
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{preprocess_file, strip_version_directive, with_version_header};
+    use std::io::TempDir;
+    use std::io::fs::File;
+
+    fn write_file(dir: &Path, name: &str, contents: &str) -> Path {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_str(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn splices_a_simple_include() {
+        let tmp = TempDir::new("shader_include_test").unwrap();
+        write_file(tmp.path(), "inc.glsl", "const float kFoo = 1.0;\n");
+        let main_path = write_file(tmp.path(), "main.glsl",
+                                   "#include \"inc.glsl\"\nvoid main() {}\n");
+
+        let result = preprocess_file(&main_path).unwrap();
+
+        assert!(result.contains("const float kFoo = 1.0;"));
+        assert!(result.contains("void main() {}"));
+        assert!(!result.contains("#include"));
+    }
+
+    #[test]
+    fn splices_a_diamond_include_only_once() {
+        let tmp = TempDir::new("shader_include_test").unwrap();
+        write_file(tmp.path(), "common.glsl", "const float kShared = 2.0;\n");
+        write_file(tmp.path(), "a.glsl", "#include \"common.glsl\"\n");
+        write_file(tmp.path(), "b.glsl", "#include \"common.glsl\"\n");
+        let main_path = write_file(tmp.path(), "main.glsl",
+                                   "#include \"a.glsl\"\n#include \"b.glsl\"\nvoid main() {}\n");
+
+        let result = preprocess_file(&main_path).unwrap();
+
+        assert_eq!(result.matches("const float kShared = 2.0;").count(), 1);
+    }
+
+    #[test]
+    fn guards_against_a_cyclic_include() {
+        let tmp = TempDir::new("shader_include_test").unwrap();
+        let a_path = write_file(tmp.path(), "a.glsl",
+                                "const float kA = 3.0;\n#include \"b.glsl\"\n");
+        write_file(tmp.path(), "b.glsl", "const float kB = 4.0;\n#include \"a.glsl\"\n");
+
+        // Must terminate instead of looping forever on the a -> b -> a cycle.
+        let result = preprocess_file(&a_path).unwrap();
+
+        assert!(result.contains("const float kA = 3.0;"));
+        assert!(result.contains("const float kB = 4.0;"));
+        assert_eq!(result.matches("const float kA = 3.0;").count(), 1);
+    }
+
+    #[test]
+    fn strips_an_existing_version_directive() {
+        let source = "#version 100\nvoid main() {}\n";
+        assert_eq!(strip_version_directive(source), "void main() {}\n");
+    }
+
+    #[test]
+    fn leaves_source_without_a_version_directive_untouched() {
+        let source = "void main() {}\n";
+        assert_eq!(strip_version_directive(source), source);
+    }
+
+    #[test]
+    fn version_header_overrides_the_files_own_version_line() {
+        let source = "#version 100\nvoid main() {}\n";
+        let versioned = with_version_header("#version 330 core\n", source);
+        assert_eq!(versioned, "#version 330 core\nvoid main() {}\n");
+    }
+}